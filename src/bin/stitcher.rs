@@ -1,6 +1,7 @@
 #[macro_use]
-extern crate log;
 extern crate clap;
+#[macro_use]
+extern crate log;
 extern crate env_logger;
 extern crate stitcher;
 
@@ -56,10 +57,28 @@ fn run() -> stitcher::Result<()> {
                 .long("output")
                 .help("Sets the output image")
                 .takes_value(true))
+        .arg(
+            Arg::with_name("allow-missing")
+                .long("allow-missing")
+                .help("Fill tiles that failed to decode or had mismatched dimensions with a transparent region instead of aborting"))
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("Force the output codec instead of inferring it from the output extension")
+                .takes_value(true))
+        .arg(
+            Arg::with_name("quality")
+                .long("quality")
+                .help("JPEG output quality, 1-100")
+                .takes_value(true))
         .get_matches();
 
+    let allow_missing = matches.is_present("allow-missing");
+    let format = matches.value_of("format");
+    let quality = value_t!(matches, "quality", u8).unwrap_or(85);
+
     if let Some(using) = matches.value_of("using") {
-        return stitcher::stitch(using);
+        return stitcher::stitch(using, allow_missing, format, quality);
     }
 
     // check if we have _all_ of the images specified, return an error otherwise
@@ -75,7 +94,16 @@ fn run() -> stitcher::Result<()> {
             if let Some(bl) = bl {
                 if let Some(br) = br {
                     if let Some(out) = out {
-                        return stitcher::stitch_images(tl, tr, bl, br, out);
+                        return stitcher::stitch_images(
+                            tl,
+                            tr,
+                            bl,
+                            br,
+                            out,
+                            allow_missing,
+                            format,
+                            quality,
+                        );
                     }
                 }
             }
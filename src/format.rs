@@ -0,0 +1,92 @@
+// Shared between `src/main.rs` and `src/lib.rs`, which otherwise carry their
+// own independent `StitcherError`/pipeline implementations (see `copy_into`,
+// `check_size`, `decode_image`, and `StitcherError` itself). Keeping output
+// format handling in one place means the two no longer have to be kept in
+// sync by hand.
+
+use image::{DynamicImage, ImageResult};
+use std::io::Write;
+use std::path::Path;
+
+/// Determine which codec to save with, preferring an explicit format
+/// override and otherwise inferring it from the output path's extension.
+/// An unrecognised extension (or override) is returned as `Err` so each
+/// caller can wrap it in its own `StitcherError::UnsupportedFormat`.
+pub fn output_format(out: &Path, format_override: Option<&str>) -> Result<image::ImageFormat, String> {
+    let extension = match format_override {
+        Some(f) => f.to_string(),
+        None => out.extension()
+            .and_then(|e| e.to_str())
+            .unwrap_or("")
+            .to_string(),
+    };
+
+    match extension.to_lowercase().as_str() {
+        "png" => Ok(image::PNG),
+        "jpg" | "jpeg" => Ok(image::JPEG),
+        "bmp" => Ok(image::BMP),
+        "tif" | "tiff" => Ok(image::TIFF),
+        "gif" => Ok(image::GIF),
+        "ico" => Ok(image::ICO),
+        "webp" => Ok(image::WEBP),
+        "pnm" => Ok(image::PNM),
+        "hdr" => Ok(image::HDR),
+        other => Err(other.to_string()),
+    }
+}
+
+/// Save the stitched image, going through the JPEG encoder directly so a
+/// `quality` can be supplied; every other format uses `DynamicImage::save`.
+pub fn save_image<W: Write>(
+    img: image::RgbaImage,
+    fout: &mut W,
+    format: image::ImageFormat,
+    quality: u8,
+) -> ImageResult<()> {
+    if format == image::JPEG {
+        let rgb = image::ImageRgba8(img).to_rgb();
+        let (width, height) = rgb.dimensions();
+        image::jpeg::JPEGEncoder::new_with_quality(fout, quality).encode(
+            &rgb.into_raw(),
+            width,
+            height,
+            image::ColorType::RGB(8),
+        )
+    } else {
+        image::ImageRgba8(img).save(fout, format)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn infers_format_from_extension_case_insensitively() {
+        assert_eq!(output_format(Path::new("out.PNG"), None).unwrap(), image::PNG);
+        assert_eq!(output_format(Path::new("out.jpg"), None).unwrap(), image::JPEG);
+        assert_eq!(output_format(Path::new("out.JPEG"), None).unwrap(), image::JPEG);
+        assert_eq!(output_format(Path::new("out.tiff"), None).unwrap(), image::TIFF);
+    }
+
+    #[test]
+    fn format_override_takes_precedence_over_extension() {
+        assert_eq!(
+            output_format(Path::new("out.png"), Some("jpeg")).unwrap(),
+            image::JPEG
+        );
+    }
+
+    #[test]
+    fn unknown_extension_is_an_error() {
+        assert_eq!(
+            output_format(Path::new("out.psd"), None),
+            Err("psd".to_string())
+        );
+    }
+
+    #[test]
+    fn missing_extension_is_an_error() {
+        assert_eq!(output_format(Path::new("out"), None), Err("".to_string()));
+    }
+}
@@ -55,9 +55,12 @@ extern crate image;
 #[macro_use]
 extern crate log;
 
+mod format;
+
 use std::io::Error as IoError;
 use std::fs::File;
-use std::path::Path;
+use std::panic;
+use std::path::{Path, PathBuf};
 
 use image::{DynamicImage, GenericImage, ImageBuffer};
 
@@ -74,6 +77,21 @@ pub enum StitcherError {
 
     #[fail(display = "Image size mismatch")] SizeMismatch,
 
+    #[fail(display = "failed to decode {:?}: {}", path, reason)]
+    DecodeFailed {
+        /// The file that could not be decoded.
+        path: PathBuf,
+        /// Why decoding failed: the `ImageError`'s message, or a fixed message
+        /// when the decoder panicked.
+        reason: String,
+    },
+
+    #[fail(display = "unsupported output format: {:?}", extension)]
+    UnsupportedFormat {
+        /// The extension (or format override) that couldn't be mapped to a codec.
+        extension: String,
+    },
+
     /// This allows you to produce any `failure::Error` within closures used by
     /// the skeleton crate. No errors of this kind will ever be produced by the
     /// crate itself.
@@ -106,6 +124,26 @@ fn check_size(img: &DynamicImage, expected_width: u32, expected_height: u32) ->
     Ok(())
 }
 
+/// Decode a single tile, converting both a returned `ImageError` and a panic
+/// inside `image::open` (malformed/truncated files are known to panic rather
+/// than error) into a `StitcherError::DecodeFailed`.
+fn decode_image<P: AsRef<Path>>(path: P) -> Result<DynamicImage> {
+    let path_buf = path.as_ref().to_path_buf();
+
+    let result = panic::catch_unwind(|| image::open(&path_buf));
+    match result {
+        Ok(Ok(img)) => Ok(img),
+        Ok(Err(e)) => Err(StitcherError::DecodeFailed {
+            path: path_buf,
+            reason: e.to_string(),
+        }),
+        Err(_) => Err(StitcherError::DecodeFailed {
+            path: path_buf,
+            reason: "the decoder panicked".to_string(),
+        }),
+    }
+}
+
 fn copy_into(
     img: &mut image::RgbaImage,
     src: &DynamicImage,
@@ -132,14 +170,19 @@ fn copy_into(
 /// ```
 /// use stitcher::stitch;
 ///
-/// stitch("artwork")?;
+/// stitch("artwork", false, None, 85)?;
 /// ```
 ///
 /// Assuming that the image files: 'artwork-tl.png', 'artwork-tl.png',
 /// 'artwork-tl.png' and 'artwork-tl.png' exist, the function will combine them
 /// into a single file called 'artwork-out.png' which is saved in the same location
 /// as the input files
-pub fn stitch(using: &str) -> Result<()> {
+pub fn stitch(
+    using: &str,
+    allow_missing: bool,
+    format_override: Option<&str>,
+    quality: u8,
+) -> Result<()> {
     info!("stitch:{}", using);
 
     let filename_tl = format!("{}-tl.png", using);
@@ -154,44 +197,133 @@ pub fn stitch(using: &str) -> Result<()> {
         &filename_bl,
         &filename_br,
         &filename_output,
+        allow_missing,
+        format_override,
+        quality,
     )
 }
 
 /// Stitch together four images given by tl, tr, bl, br. Saving the result as the filename given in out
 ///
+/// Every tile that fails to decode or doesn't match the dimensions of the
+/// first successfully decoded tile is collected rather than aborting on the
+/// first problem. When `allow_missing` is `false` (the default via the
+/// `stitcher` binary) the first such failure is returned as an error after
+/// all of them have been logged; when `true`, those cells are instead left
+/// as a transparent region in the output. If no tile decodes at all there is
+/// no cell size to use, and `SizeMismatch` is returned even when
+/// `allow_missing` is `true`.
+///
 /// # Example
 ///
 /// ```
 /// use stitcher::stitch;
 ///
-/// stitch_images("artwork-top-left.png", "artwork-top-right.png", "artwork-bottom-left.png", "artwork-bottom-right.png", "result.png")?;
+/// stitch_images("artwork-top-left.png", "artwork-top-right.png", "artwork-bottom-left.png", "artwork-bottom-right.png", "result.png", false, None, 85)?;
 /// ```
-pub fn stitch_images<P>(tl: P, tr: P, bl: P, br: P, out: P) -> Result<()>
+pub fn stitch_images<P>(
+    tl: P,
+    tr: P,
+    bl: P,
+    br: P,
+    out: P,
+    allow_missing: bool,
+    format_override: Option<&str>,
+    quality: u8,
+) -> Result<()>
 where P: AsRef<Path>,
       P: std::fmt::Debug {
     info!("stitch_images: {:?} {:?} {:?} {:?} -> {:?}", tl, tr, bl, br, out);
 
-    let img_tl = image::open(tl)?;
-    let img_tr = image::open(tr)?;
-    let img_bl = image::open(bl)?;
-    let img_br = image::open(br)?;
+    let tiles: Vec<(PathBuf, u32, u32)> = vec![
+        (tl.as_ref().to_path_buf(), 0, 0),
+        (tr.as_ref().to_path_buf(), 1, 0),
+        (bl.as_ref().to_path_buf(), 0, 1),
+        (br.as_ref().to_path_buf(), 1, 1),
+    ];
 
-    // all images should have the same dimensions
-    let (width, height) = img_tl.dimensions();
-    check_size(&img_tr, width, height)?;
-    check_size(&img_bl, width, height)?;
-    check_size(&img_br, width, height)?;
+    // a malformed file can make `image::open` panic rather than return an
+    // `Err`, so guard each call individually and let the rest of the tiles
+    // keep decoding. Suppress the default panic handler for the duration so a
+    // corrupt tile only produces the structured `DecodeFailed` report below,
+    // not a raw "thread panicked" dump on stderr.
 
-    // Construct a new ImageBuffer for all 4 images
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let decoded: Vec<(PathBuf, u32, u32, Result<DynamicImage>)> = tiles
+        .into_iter()
+        .map(|(path, gx, gy)| {
+            let result = decode_image(&path);
+            (path, gx, gy, result)
+        })
+        .collect();
+
+    panic::set_hook(previous_hook);
+
+    // dimensions of the first successfully decoded tile. This is intentionally
+    // not resolved with `?` here: when all four tiles fail to decode there's
+    // no size to find, but that must fall through to the failures loop below
+    // so every file's `DecodeFailed` reason gets logged and `allow_missing`
+    // gets consulted, rather than aborting early with an opaque
+    // `SizeMismatch`. Only after that is the missing size reported as
+    // `SizeMismatch`, whatever `allow_missing` is.
+    let size = decoded
+        .iter()
+        .filter_map(|&(_, _, _, ref result)| result.as_ref().ok())
+        .map(|img| img.dimensions())
+        .next();
+
+    let mut failures: Vec<(PathBuf, StitcherError)> = Vec::new();
+    let mut tiles: Vec<Option<(u32, u32, DynamicImage)>> = Vec::with_capacity(decoded.len());
+
+    for (path, gx, gy, result) in decoded {
+        let tile = match result {
+            Ok(img) => match size {
+                // `size` is only `None` when no tile decoded, so there can be
+                // no `Ok` tile left to match against it here.
+                None => unreachable!("a decoded tile implies `size` was found"),
+                Some((width, height)) => match check_size(&img, width, height) {
+                    Ok(()) => Some((gx, gy, img)),
+                    Err(e) => {
+                        failures.push((path, e));
+                        None
+                    }
+                },
+            },
+            Err(e) => {
+                failures.push((path, e));
+                None
+            }
+        };
+        tiles.push(tile);
+    }
+
+    if !failures.is_empty() {
+        error!("{} of 4 tiles could not be used:", failures.len());
+        for (path, err) in &failures {
+            error!("  {:?}: {}", path, err);
+        }
+
+        if !allow_missing {
+            let (_, first_failure) = failures.into_iter().next().unwrap();
+            return Err(first_failure);
+        }
+    }
+
+    let (width, height) = size.ok_or(StitcherError::SizeMismatch)?;
     let mut img = ImageBuffer::new(width * 2, height * 2);
 
-    copy_into(&mut img, &img_tl, 0, 0, width, height)?;
-    copy_into(&mut img, &img_tr, width, 0, width, height)?;
-    copy_into(&mut img, &img_bl, 0, height, width, height)?;
-    copy_into(&mut img, &img_br, width, height, width, height)?;
+    for tile in tiles {
+        if let Some((gx, gy, block)) = tile {
+            copy_into(&mut img, &block, gx * width, gy * height, width, height)?;
+        }
+    }
 
-    let ref mut fout = File::create(out)?;
-    image::ImageRgba8(img).save(fout, image::PNG)?;
+    let out_format = format::output_format(out.as_ref(), format_override)
+        .map_err(|extension| StitcherError::UnsupportedFormat { extension })?;
+    let mut fout = File::create(out)?;
+    format::save_image(img, &mut fout, out_format, quality)?;
 
     Ok(())
 }
@@ -42,12 +42,18 @@ extern crate failure;
 extern crate image;
 #[macro_use]
 extern crate log;
+extern crate rayon;
+
+mod format;
 
 use clap::{App, Arg};
+use rayon::prelude::*;
 use std::io::Error as IoError;
 use std::fs::File;
+use std::panic;
+use std::path::{Path, PathBuf};
 
-use image::{DynamicImage, GenericImage, ImageBuffer, ImageResult, RgbaImage};
+use image::{DynamicImage, GenericImage, ImageBuffer, RgbaImage};
 
 /// A specialized `Result` type for the `Stitcher` crate.
 pub type Result<T> = ::std::result::Result<T, StitcherError>;
@@ -70,14 +76,14 @@ fn run() -> Result<()> {
             Arg::with_name("width")
                 .short("x")
                 .long("width")
-                .help("The number of images along the x-axis")
+                .help("The number of images along the x-axis, inferred from the image count if omitted")
                 .takes_value(true),
         )
         .arg(
             Arg::with_name("height")
                 .short("y")
                 .long("height")
-                .help("The number of images along the y-axis")
+                .help("The number of images along the y-axis, inferred from the image count if omitted")
                 .takes_value(true),
         )
         .arg(
@@ -95,6 +101,37 @@ fn run() -> Result<()> {
                 .help("Sets the output image")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("allow-missing")
+                .long("allow-missing")
+                .help(
+                    "Fill tiles that failed to decode or had mismatched dimensions with a \
+                     transparent region instead of aborting",
+                ),
+        )
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .help("Force the output codec instead of inferring it from the output extension")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quality")
+                .long("quality")
+                .help("JPEG output quality, 1-100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("resize")
+                .long("resize")
+                .help("Rescale tiles that don't match the target cell size instead of treating them as a failure"),
+        )
+        .arg(
+            Arg::with_name("cell-size")
+                .long("cell-size")
+                .help("Target tile size as WxH, e.g. 800x600, used with --resize. Defaults to the dimensions of the first image that decodes")
+                .takes_value(true),
+        )
         .get_matches();
 
     // get command line arguments
@@ -104,8 +141,14 @@ fn run() -> Result<()> {
         .map(|vals| vals.collect::<Vec<_>>())
         .unwrap_or(Vec::new());
 
-    let x = value_t!(matches, "width", u32).unwrap_or(1);
-    let y = value_t!(matches, "height", u32).unwrap_or(1);
+    let x_arg = value_t!(matches, "width", u32).ok();
+    let y_arg = value_t!(matches, "height", u32).ok();
+    let allow_missing = matches.is_present("allow-missing");
+    let resize = matches.is_present("resize");
+    let cell_size = match matches.value_of("cell-size") {
+        Some(s) => Some(parse_cell_size(s)?),
+        None => None,
+    };
 
     let output;
     if let Some(o) = matches.value_of("output") {
@@ -114,92 +157,211 @@ fn run() -> Result<()> {
         return Err(StitcherError::CommandLineParsingError);
     }
 
-    // sanity check command line arguments
+    // sanity check command line arguments; when neither -x nor -y is given,
+    // infer a near-square grid that can hold every supplied image instead of
+    // requiring the caller to work out the layout themselves
 
-    if filenames.len() as u32 != x * y {
-        error!(
-            "width:{} x height:{} mismatch with given images:{}, expected:{}",
-            x,
-            y,
-            filenames.len(),
-            x * y
-        );
+    if x_arg == Some(0) || y_arg == Some(0) {
+        error!("width and height must both be at least 1");
         return Err(StitcherError::CommandLineParsingError);
+    }
+
+    let (x, y) = match (x_arg, y_arg) {
+        (Some(x), Some(y)) => {
+            if filenames.len() as u32 != x * y {
+                error!(
+                    "width:{} x height:{} mismatch with given images:{}, expected:{}",
+                    x,
+                    y,
+                    filenames.len(),
+                    x * y
+                );
+                return Err(StitcherError::CommandLineParsingError);
+            }
+            (x, y)
+        }
+        (Some(x), None) => (x, ceil_div(filenames.len() as u32, x)),
+        (None, Some(y)) => (ceil_div(filenames.len() as u32, y), y),
+        (None, None) => infer_grid(filenames.len() as u32),
     };
 
-    // check image dimensions
+    // decode every tile in parallel; a malformed file can make `image::open`
+    // panic rather than return an `Err`, so guard each call individually and
+    // let the rest of the grid keep decoding. Suppress the default panic
+    // handler for the duration so a corrupt tile only produces the structured
+    // `DecodeFailed` report below, not a raw "thread panicked" dump on stderr.
+
+    let previous_hook = panic::take_hook();
+    panic::set_hook(Box::new(|_| {}));
+
+    let images: Vec<(PathBuf, Result<DynamicImage>)> = filenames
+        .into_par_iter()
+        .map(|f| (PathBuf::from(f), decode_image(f)))
+        .collect();
+
+    panic::set_hook(previous_hook);
+
+    // dimensions of the first successfully decoded tile, used as the cell
+    // size when `--cell-size` isn't given. This is intentionally not resolved
+    // with `?` here: when every tile fails to decode there's no size to find,
+    // but that must fall through to the failures loop below so every file's
+    // `DecodeFailed` reason gets logged and `allow_missing` gets consulted,
+    // rather than aborting early with an opaque `SizeMismatch`. Only after
+    // that is the missing size reported as `SizeMismatch`, even with
+    // `--allow-missing`, unless `--cell-size` supplied one.
+    let size = match cell_size {
+        Some(wh) => Some(wh),
+        None => size_of_first(&images),
+    };
 
-    let images: Vec<ImageResult<DynamicImage>> =
-        filenames.into_iter().map(|f| image::open(f)).collect();
+    // accumulate every tile that couldn't be opened or didn't match the grid's
+    // dimensions instead of collapsing them into a single opaque error, and
+    // leave a `None` placeholder so the corresponding cell can be filled with
+    // a transparent region below. With `--resize`, a mismatched tile is
+    // rescaled to the target cell size instead of being treated as a failure.
+    let mut failures: Vec<(PathBuf, StitcherError)> = Vec::new();
+    let mut tiles: Vec<Option<DynamicImage>> = Vec::with_capacity(images.len());
+
+    for (path, result) in images {
+        let tile = match result {
+            Ok(img) => match size {
+                // `size` is only `None` when no tile decoded, so there can be
+                // no `Ok` tile left to match against it here.
+                None => unreachable!("a decoded tile implies `size` was found"),
+                Some((width, height)) => match check_size(&img, width, height) {
+                    Ok(()) => Some(img),
+                    Err(e) => {
+                        if resize {
+                            Some(img.resize_exact(width, height, image::FilterType::Lanczos3))
+                        } else {
+                            failures.push((path, e));
+                            None
+                        }
+                    }
+                },
+            },
+            Err(e) => {
+                failures.push((path, e));
+                None
+            }
+        };
+        tiles.push(tile);
+    }
 
-    let (width, height) = size_of_first(&images)?;
-    check_dimensions(&images, width, height)?;
+    if !failures.is_empty() {
+        error!(
+            "{} of {} tiles could not be used:",
+            failures.len(),
+            tiles.len()
+        );
+        for (path, err) in &failures {
+            error!("  {:?}: {}", path, err);
+        }
+
+        if !allow_missing {
+            let (_, first_failure) = failures.into_iter().next().unwrap();
+            return Err(first_failure);
+        }
+    }
 
     // create the combined image
 
+    let (width, height) = size.ok_or(StitcherError::SizeMismatch)?;
     let mut img: RgbaImage = ImageBuffer::new(width * x, height * y);
-    let mut iter = images.iter();
+    let mut iter = tiles.into_iter();
 
     for yy in 0..y {
         for xx in 0..x {
-            if let Some(block) = iter.next() {
-                if let &Ok(ref block_) = block {
-                    copy_into(&mut img, &block_, xx * width, yy * height, width, height)?;
-                }
+            if let Some(Some(block)) = iter.next() {
+                copy_into(&mut img, &block, xx * width, yy * height, width, height)?;
             }
         }
     }
 
-    // save to disk
+    // save to disk, inferring the codec from the output extension unless
+    // `--format` overrides it
 
-    let ref mut fout = File::create(output)?;
-    image::ImageRgba8(img).save(fout, image::PNG)?;
+    let output_format = format::output_format(Path::new(output), matches.value_of("format"))
+        .map_err(|extension| StitcherError::UnsupportedFormat { extension })?;
+    let quality = value_t!(matches, "quality", u8).unwrap_or(85);
+    let mut fout = File::create(output)?;
+    format::save_image(img, &mut fout, output_format, quality)?;
 
     Ok(())
 }
 
-fn size_of_first(images: &Vec<ImageResult<DynamicImage>>) -> Result<(u32, u32)> {
-    // get the size of the first image
-    //
-    let first = images.into_iter().nth(0).unwrap();
+/// Decode a single tile, converting both a returned `ImageError` and a panic
+/// inside `image::open` (malformed/truncated files are known to panic rather
+/// than error) into a `StitcherError::DecodeFailed`.
+fn decode_image(path: &str) -> Result<DynamicImage> {
+    let path_buf = PathBuf::from(path);
+
+    match panic::catch_unwind(|| image::open(path)) {
+        Ok(Ok(img)) => Ok(img),
+        Ok(Err(e)) => Err(StitcherError::DecodeFailed {
+            path: path_buf,
+            reason: e.to_string(),
+        }),
+        Err(_) => Err(StitcherError::DecodeFailed {
+            path: path_buf,
+            reason: "the decoder panicked".to_string(),
+        }),
+    }
+}
 
-    if let &Ok(ref first_image) = first {
-        Ok(first_image.dimensions())
+fn ceil_div(n: u32, d: u32) -> u32 {
+    if d == 0 {
+        0
     } else {
-        Err(StitcherError::SizeMismatch)
+        (n + d - 1) / d
     }
 }
 
-fn check_dimensions(
-    images: &Vec<ImageResult<DynamicImage>>,
-    width: u32,
-    height: u32,
-) -> Result<()> {
-    // compare the rest of the images with the size of the first image
-    //
-    let res = images
-        .into_iter()
-        .skip(1)
-        .all(|ref image| is_same_size(&image, width, height));
-
-    if res == true {
-        Ok(())
-    } else {
-        Err(StitcherError::SizeMismatch)
+/// Infer a near-square x*y grid large enough to hold `count` tiles, used
+/// when neither `-x` nor `-y` is given explicitly.
+fn infer_grid(count: u32) -> (u32, u32) {
+    if count == 0 {
+        return (1, 1);
     }
+
+    let x = (count as f64).sqrt().ceil() as u32;
+    let y = ceil_div(count, x);
+    (x, y)
 }
 
-fn is_same_size(
-    image: &std::result::Result<image::DynamicImage, image::ImageError>,
-    width: u32,
-    height: u32,
-) -> bool {
-    if let &Ok(ref img) = image {
-        let (width_, height_) = img.dimensions();
-        return width_ == width && height_ == height;
+/// Parse a `--cell-size` value formatted as `WxH`.
+fn parse_cell_size(s: &str) -> Result<(u32, u32)> {
+    let mut parts = s.splitn(2, 'x');
+    let width = parts.next().and_then(|w| w.parse().ok());
+    let height = parts.next().and_then(|h| h.parse().ok());
+
+    match (width, height) {
+        (Some(w), Some(h)) => Ok((w, h)),
+        _ => Err(StitcherError::CommandLineParsingError),
     }
+}
 
-    false
+fn size_of_first(images: &Vec<(PathBuf, Result<DynamicImage>)>) -> Option<(u32, u32)> {
+    // dimensions of the first tile that actually decoded, not necessarily
+    // images[0] - mirrors `stitch_images` in lib.rs, so a single corrupt
+    // leading tile doesn't stop the rest of the grid's failures (and
+    // `--allow-missing` fallback) from being reported. `None` when nothing
+    // decoded at all, left for the caller to decide how to report that.
+    images
+        .iter()
+        .filter_map(|&(_, ref result)| result.as_ref().ok())
+        .map(|img| img.dimensions())
+        .next()
+}
+
+fn check_size(img: &DynamicImage, expected_width: u32, expected_height: u32) -> Result<()> {
+    let (width, height) = img.dimensions();
+
+    if width != expected_width || height != expected_height {
+        return Err(StitcherError::SizeMismatch);
+    }
+
+    Ok(())
 }
 
 fn copy_into(
@@ -226,6 +388,21 @@ pub enum StitcherError {
 
     #[fail(display = "Image size mismatch")] SizeMismatch,
 
+    #[fail(display = "failed to decode {:?}: {}", path, reason)]
+    DecodeFailed {
+        /// The file that could not be decoded.
+        path: PathBuf,
+        /// Why decoding failed: the `ImageError`'s message, or a fixed message
+        /// when the decoder panicked.
+        reason: String,
+    },
+
+    #[fail(display = "unsupported output format: {:?}", extension)]
+    UnsupportedFormat {
+        /// The extension (or `--format` value) that couldn't be mapped to a codec.
+        extension: String,
+    },
+
     /// This allows you to produce any `failure::Error` within closures used by
     /// the skeleton crate. No errors of this kind will ever be produced by the
     /// crate itself.
@@ -247,3 +424,63 @@ impl From<image::ImageError> for StitcherError {
         StitcherError::ImageError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ceil_div_rounds_up() {
+        assert_eq!(ceil_div(0, 5), 0);
+        assert_eq!(ceil_div(10, 5), 2);
+        assert_eq!(ceil_div(11, 5), 3);
+        assert_eq!(ceil_div(5, 0), 0);
+    }
+
+    #[test]
+    fn infer_grid_handles_empty_and_single_counts() {
+        assert_eq!(infer_grid(0), (1, 1));
+        assert_eq!(infer_grid(1), (1, 1));
+    }
+
+    #[test]
+    fn infer_grid_is_square_for_perfect_squares() {
+        assert_eq!(infer_grid(4), (2, 2));
+        assert_eq!(infer_grid(9), (3, 3));
+    }
+
+    #[test]
+    fn infer_grid_holds_every_tile_for_non_square_counts() {
+        for count in 1..50 {
+            let (x, y) = infer_grid(count);
+            assert!(
+                x * y >= count,
+                "grid {}x{} too small for {} tiles",
+                x,
+                y,
+                count
+            );
+        }
+    }
+
+    #[test]
+    fn infer_grid_is_near_square_for_a_prime_count() {
+        // 7 is prime, so only a 1x7 or 7x1 grid fits it without a blank cell;
+        // the near-square grid leaves two cells blank
+        assert_eq!(infer_grid(7), (3, 3));
+    }
+
+    #[test]
+    fn parse_cell_size_accepts_wxh() {
+        let (width, height) = parse_cell_size("800x600").unwrap();
+        assert_eq!(width, 800);
+        assert_eq!(height, 600);
+    }
+
+    #[test]
+    fn parse_cell_size_rejects_malformed_input() {
+        assert!(parse_cell_size("800").is_err());
+        assert!(parse_cell_size("x600").is_err());
+        assert!(parse_cell_size("wide").is_err());
+    }
+}